@@ -21,11 +21,22 @@ use no_panic::no_panic;
 
 const MAX_SHMEMS: usize = 10_000;
 const MIN_OBJECT_SIZE: usize = 8;
+// `ObjectSize.0` is a `NonZeroU8`, so this covers every size class.
+const MAX_OBJECT_SIZES: usize = 256;
 
 struct ShmemMetadata {
     num_shmems: AtomicUsize,
     shmem_free: [AtomicBool; MAX_SHMEMS],
     shmem_names: [ShmemName; MAX_SHMEMS],
+    // Number of objects currently live in each shmem segment. When this
+    // drops to zero, `try_reclaim_shmem` strips that segment's entries
+    // out of its size class's free list and, if it's still drained
+    // afterwards, hands it back via `free_shmem`.
+    live_objects: [AtomicUsize; MAX_SHMEMS],
+    // Per-size-class Treiber-stack heads of freed objects, indexed by
+    // `ObjectSize.0`. Lives in the shared metadata (unlike `unused`) so
+    // that an object freed in one process can be recycled by another.
+    free_lists: [AtomicSharedAddress; MAX_OBJECT_SIZES],
 }
 
 pub struct ShmemAllocator {
@@ -35,6 +46,8 @@ pub struct ShmemAllocator {
     shmem_names: *mut ShmemName,
     shmems: *mut AtomicPtr<SharedMem>,
     unused: *mut AtomicSharedAddress,
+    free_lists: *mut AtomicSharedAddress,
+    live_objects: *mut AtomicUsize,
 }
 
 unsafe impl Sync for ShmemAllocator {}
@@ -47,6 +60,8 @@ impl ShmemAllocator {
         let num_shmems = &mut (*metadata).num_shmems;
         let shmem_free = &mut (*metadata).shmem_free[0];
         let shmem_names = &mut (*metadata).shmem_names[0];
+        let live_objects = &mut (*metadata).live_objects[0];
+        let free_lists = &mut (*metadata).free_lists[0];
         let shmems = Box::into_raw(Box::new(mem::zeroed()));
         let unused = Box::into_raw(Box::new(mem::zeroed()));
         ShmemAllocator {
@@ -56,6 +71,8 @@ impl ShmemAllocator {
             shmem_names,
             shmems,
             unused,
+            free_lists,
+            live_objects,
         }
     }
 
@@ -136,8 +153,131 @@ impl ShmemAllocator {
     }
 
     #[cfg_attr(feature = "no-panic", no_panic)]
-    unsafe fn free_shmem(&self, shmem_id: ShmemId) {
-        // TODO
+    unsafe fn free_shmem(&self, shmem_id: ShmemId, object_size: ObjectSize) {
+        if (&*self.live_objects.offset(shmem_id.0 as isize)).load(Ordering::SeqCst) != 0 {
+            return;
+        }
+        let atomic_unused = &*self.unused.offset(object_size.0.get() as isize);
+        if let Some(unused) = atomic_unused.load(Ordering::SeqCst) {
+            if unused.shmem_id() == shmem_id {
+                // Still the active bump target for this size class.
+                return;
+            }
+        }
+        if (&*self.shmem_free.offset(shmem_id.0 as isize)).swap(false, Ordering::SeqCst) {
+            let atomic_shmem = &*self.shmems.offset(shmem_id.0 as isize);
+            if let Some(shmem) = atomic_shmem.swap(ptr::null_mut(), Ordering::SeqCst).as_mut() {
+                drop(Box::from_raw(shmem));
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    unsafe fn mark_alloc(&self, addr: SharedAddress) {
+        (&*self.live_objects.offset(addr.shmem_id().0 as isize)).fetch_add(1, Ordering::SeqCst);
+    }
+
+    // I'd like to be able to mark this as `no_panic` but unfortunately
+    // `get_bytes` can go through `get_shmem`, which can panic when
+    // opening a shared memory file.
+    unsafe fn write_next(&self, addr: SharedAddress, next: Option<SharedAddress>) {
+        if let Some(ptr) = self.get_bytes(addr) {
+            let bits = next.map(|next| next.as_raw().to_u64()).unwrap_or(0);
+            (ptr.as_ptr() as *mut u64).write_volatile(bits);
+        }
+    }
+
+    // I'd like to be able to mark this as `no_panic` but unfortunately
+    // `get_bytes` can go through `get_shmem`, which can panic when
+    // opening a shared memory file.
+    unsafe fn read_next(&self, addr: SharedAddress) -> Option<SharedAddress> {
+        let ptr = self.get_bytes(addr)?;
+        let bits = (ptr.as_ptr() as *mut u64).read_volatile();
+        SharedAddress::from_raw(RawSharedAddress::from_u64(bits))
+    }
+
+    // Pop the head of the free list for `object_size`, if there is one.
+    unsafe fn pop_free(&self, object_size: ObjectSize) -> Option<SharedAddress> {
+        let atomic_head = &*self.free_lists.offset(object_size.0.get() as isize);
+        loop {
+            let head = atomic_head.load(Ordering::SeqCst)?;
+            let next = self.read_next(head);
+            if Some(head) == atomic_head.compare_and_swap(Some(head), next, Ordering::SeqCst) {
+                self.mark_alloc(head);
+                return Some(head);
+            }
+        }
+    }
+
+    // Push `addr` onto the head of the free list for `object_size`,
+    // bumping the generation tag to guard the CAS against ABA.
+    unsafe fn push_free(&self, object_size: ObjectSize, addr: SharedAddress) {
+        let atomic_head = &*self.free_lists.offset(object_size.0.get() as isize);
+        loop {
+            let head = atomic_head.load(Ordering::SeqCst);
+            self.write_next(addr, head);
+            let generation = head.map(|head| head.generation().wrapping_add(1)).unwrap_or(1);
+            let new_head = Some(addr.with_generation(generation));
+            if head == atomic_head.compare_and_swap(head, new_head, Ordering::SeqCst) {
+                return;
+            }
+        }
+    }
+
+    // Atomically detach the whole free list for `object_size`, handing
+    // its head back to the caller. Any push/pop racing against this
+    // just sees (or installs) a fresh empty list, so this can't race
+    // unsoundly; it only costs the list's reuse for `object_size` until
+    // the caller splices surviving entries back in.
+    unsafe fn detach_free_list(&self, object_size: ObjectSize) -> Option<SharedAddress> {
+        let atomic_head = &*self.free_lists.offset(object_size.0.get() as isize);
+        loop {
+            let head = atomic_head.load(Ordering::SeqCst);
+            if head == atomic_head.compare_and_swap(head, None, Ordering::SeqCst) {
+                return head;
+            }
+        }
+    }
+
+    // Called when `shmem_id`'s live-object count has just dropped to
+    // zero. A drained segment can't be reclaimed just because of that:
+    // every object ever allocated from it is, once freed, necessarily
+    // still sitting somewhere on `object_size`'s free list (freeing
+    // never removes an entry by itself), so the list is exactly what's
+    // keeping a would-be-dangling pointer into the segment alive.
+    // Detach the whole list, strip out the entries that belong to
+    // `shmem_id` (safe: we're about to drop that segment anyway), and
+    // splice the survivors back in before handing the segment to
+    // `free_shmem`.
+    unsafe fn try_reclaim_shmem(&self, shmem_id: ShmemId, object_size: ObjectSize) {
+        if (&*self.live_objects.offset(shmem_id.0 as isize)).load(Ordering::SeqCst) != 0 {
+            return;
+        }
+        let atomic_unused = &*self.unused.offset(object_size.0.get() as isize);
+        if let Some(unused) = atomic_unused.load(Ordering::SeqCst) {
+            if unused.shmem_id() == shmem_id {
+                // Still the active bump target for this size class.
+                return;
+            }
+        }
+        let mut survivors = Vec::new();
+        let mut next = self.detach_free_list(object_size);
+        while let Some(addr) = next {
+            next = self.read_next(addr);
+            if addr.shmem_id() != shmem_id {
+                survivors.push(addr);
+            }
+        }
+        for addr in survivors {
+            self.push_free(object_size, addr);
+        }
+        // A concurrent pop could have raced us between the live-object
+        // check above and the detach, turning this segment live again
+        // (and taking its address off the list before we got to it, so
+        // it's not in `survivors` either); re-check before reclaiming.
+        if (&*self.live_objects.offset(shmem_id.0 as isize)).load(Ordering::SeqCst) == 0 {
+            self.free_shmem(shmem_id, object_size);
+        }
     }
 
     pub fn get_bytes(&self, address: SharedAddress) -> Option<NonNull<u8>> {
@@ -150,6 +290,9 @@ impl ShmemAllocator {
 
     pub unsafe fn alloc_bytes(&self, size: usize) -> Option<SharedAddress> {
         let object_size = ObjectSize::ceil(size);
+        if let Some(addr) = self.pop_free(object_size) {
+            return Some(addr);
+        }
         let atomic_unused = &*self.unused.offset(object_size.0.get() as isize);
         loop {
             let mut old_size = 0;
@@ -158,6 +301,7 @@ impl ShmemAllocator {
                 if let Some(shmem) = self.get_shmem(unused.shmem_id()) {
                     old_size = shmem.get_size();
                     if unused.object_end().as_usize() <= old_size {
+                        self.mark_alloc(unused);
                         return Some(unused);
                     }
                 }
@@ -171,16 +315,24 @@ impl ShmemAllocator {
                 object_size.as_offset(),
             ));
             if unused == atomic_unused.compare_and_swap(unused, new_unused, Ordering::SeqCst) {
+                self.mark_alloc(result);
                 return Some(result);
             } else {
-                self.free_shmem(new_shmem_id);
+                self.free_shmem(new_shmem_id, object_size);
             }
         }
     }
 
-    #[cfg_attr(feature = "no-panic", no_panic)]
+    // I'd like to be able to mark this as `no_panic` but unfortunately
+    // `push_free` can go through `get_shmem`, which can panic when
+    // opening a shared memory file.
     pub unsafe fn free_bytes(&self, addr: SharedAddress) {
-        // TODO
+        let object_size = addr.object_size();
+        self.push_free(object_size, addr);
+        let live = &*self.live_objects.offset(addr.shmem_id().0 as isize);
+        if live.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.try_reclaim_shmem(addr.shmem_id(), object_size);
+        }
     }
 }
 
@@ -189,7 +341,10 @@ impl ShmemAllocator {
 struct RawSharedAddress {
     shmem_id: u16,
     object_size: u8,
-    padding: u8,
+    // A free-list generation tag, bumped on every push to guard the
+    // Treiber-stack CAS against ABA. Ignored when computing the shmem
+    // id or offset.
+    generation: u8,
     object_offset: u32,
 }
 
@@ -206,7 +361,7 @@ impl RawSharedAddress {
 
     #[cfg_attr(feature = "no-panic", no_panic)]
     fn is_valid(self) -> bool {
-        (self.object_size != 0) && (self.padding == 0)
+        self.object_size != 0
     }
 }
 
@@ -239,12 +394,24 @@ impl SharedAddress {
             SharedAddress::from_raw_unchecked(RawSharedAddress {
                 shmem_id: shmem_id.0,
                 object_size: size.0.get(),
-                padding: 0,
+                generation: 0,
                 object_offset: offset.0,
             })
         }
     }
 
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    fn with_generation(self, generation: u8) -> SharedAddress {
+        let mut raw = self.as_raw();
+        raw.generation = generation;
+        unsafe { SharedAddress::from_raw_unchecked(raw) }
+    }
+
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    fn generation(self) -> u8 {
+        self.as_raw().generation
+    }
+
     #[cfg_attr(feature = "no-panic", no_panic)]
     fn shmem_id(self) -> ShmemId {
         ShmemId(self.as_raw().shmem_id)
@@ -285,6 +452,11 @@ impl AtomicSharedAddress {
         SharedAddress::from_raw(RawSharedAddress::from_u64(bits))
     }
 
+    #[cfg_attr(feature = "no-panic", no_panic)]
+    fn load(&self, order: Ordering) -> Option<SharedAddress> {
+        SharedAddress::from_raw(RawSharedAddress::from_u64(self.0.load(order)))
+    }
+
     #[cfg_attr(feature = "no-panic", no_panic)]
     fn fetch_add(&self, offset: ObjectOffset, order: Ordering) -> Option<SharedAddress> {
         let bits = self.0.fetch_add(offset.as_u64(), order);
@@ -437,3 +609,24 @@ fn test_shared_box() {
     let val = unsafe { ptr.read_volatile() };
     assert_eq!(val, 37);
 }
+
+// `ALLOCATOR` is a single process-wide static and `#[test]`s run
+// concurrently in the same process by default, so this uses a size
+// class ([u8; 32], unlike `usize` above) that no other test in this
+// file allocates from, to avoid racing `test_shared_box` on the same
+// free list.
+#[test]
+fn test_shared_box_reuses_freed_slot() {
+    let first: SharedBox<[u8; 32]> = SharedBox::new([11; 32]);
+    let freed_shmem_id = first.address().shmem_id();
+    let freed_offset = first.address().object_offset();
+    drop(first);
+
+    let second: SharedBox<[u8; 32]> = SharedBox::new([22; 32]);
+    assert_eq!(second.address().shmem_id(), freed_shmem_id);
+    assert_eq!(second.address().object_offset(), freed_offset);
+
+    let ptr = second.as_ptr().unwrap().as_ptr();
+    let val = unsafe { ptr.read_volatile() };
+    assert_eq!(val, [22; 32]);
+}